@@ -1,14 +1,86 @@
+#![allow(clippy::needless_return)]
+
+use std::collections::HashMap;
 use std::env;
-use std::fmt::format;
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Duration;
 
-use hyper::{Body, Method, Request, Response, Server};
+use bb8::Pool;
+use bb8_redis::RedisConnectionManager;
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
 use hyper::client::{Client, HttpConnector};
 use hyper::service::{make_service_fn, service_fn};
 use hyper_tls::HttpsConnector;
+use redis::AsyncCommands;
+use ring::hmac;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+use tracing::Instrument;
+
+const DEFAULT_CACHE_TTL: u64 = 3600;
+const MAX_RATE_LIMIT_ATTEMPTS: u32 = 3;
+const USER_ENDPOINT: &str = "GET /users/:id";
+const DEFAULT_SHUTDOWN_TIMEOUT_SECS: u64 = 30;
+
+#[derive(Debug)]
+struct RateLimitExhausted {
+    retry_after: u64,
+}
+
+impl std::fmt::Display for RateLimitExhausted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "exhausted retries waiting for Discord rate limit, retry after {}s", self.retry_after)
+    }
+}
+
+impl std::error::Error for RateLimitExhausted {}
+
+struct BucketState {
+    remaining: i64,
+    reset_at: Instant,
+}
+
+#[derive(Default)]
+struct RateLimiter {
+    buckets: Mutex<HashMap<String, BucketState>>,
+    endpoints: Mutex<HashMap<String, String>>,
+    global_until: Mutex<Option<Instant>>,
+}
+
+impl RateLimiter {
+    async fn wait_if_needed(&self, endpoint: &str) {
+        let global_until = *self.global_until.lock().await;
+        if let Some(global_until) = global_until {
+            tokio::time::sleep_until(global_until).await;
+        }
+        let bucket_name = self.endpoints.lock().await.get(endpoint).cloned();
+        let Some(bucket_name) = bucket_name else { return; };
+        let reset_at = {
+            let buckets = self.buckets.lock().await;
+            buckets.get(&bucket_name).filter(|b| b.remaining <= 0).map(|b| b.reset_at)
+        };
+        if let Some(reset_at) = reset_at {
+            tokio::time::sleep_until(reset_at).await;
+        }
+    }
+
+    async fn record(&self, endpoint: &str, bucket: Option<String>, remaining: Option<i64>, reset_after: Option<f64>) {
+        if let (Some(bucket), Some(remaining), Some(reset_after)) = (bucket, remaining, reset_after) {
+            self.endpoints.lock().await.insert(endpoint.to_string(), bucket.clone());
+            self.buckets.lock().await.insert(bucket, BucketState {
+                remaining,
+                reset_at: Instant::now() + Duration::from_secs_f64(reset_after),
+            });
+        }
+    }
+
+    async fn pause_global(&self, until: Instant) {
+        *self.global_until.lock().await = Some(until);
+    }
+}
 
 macro_rules! unwrap_resp {
     ($x:expr) => {
@@ -19,7 +91,7 @@ macro_rules! unwrap_resp {
     };
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug)]
 struct DiscordUserFormat {
     accent_color: Option<i64>,
     username: String,
@@ -31,29 +103,138 @@ struct DiscordUserFormat {
     avatar: Option<String>,
 }
 
-async fn get_user_data(client: &Client<HttpsConnector<HttpConnector>>, token: &str, user_id: u64) -> anyhow::Result<DiscordUserFormat> {
-    let request = Request::builder()
-        .method(Method::GET)
-        .uri(format!("https://discord.com/api/v10/users/{}", user_id))
-        .header("accept", "application/json")
-        .header("authorization", format!("Bot {}", token))
-        .body(Body::empty())?;
-    let mut x = client.request(request).await?;
-    let body = hyper::body::to_bytes(x.body_mut()).await?;
-    let json_data = String::from_utf8(Vec::from(body))?;
-    let json: DiscordUserFormat = serde_json::from_str(&json_data)?;
-    Ok(json)
-}
-
-fn get_avatar_url(json: &DiscordUserFormat) -> anyhow::Result<String> {
-    println!("Served request for {}: {}#{}", json.id, json.username, json.discriminator);
-    let avatar_url = match &json.avatar {
-        None => default_avatar_url(&json.discriminator)?,
-        Some(avatar_hash) => format!("https://cdn.discordapp.com/avatars/{}/{}.png", json.id, avatar_hash)
+async fn get_cached_user_data(arc: &Arc<Ctx>, user_id: u64) -> Option<DiscordUserFormat> {
+    let mut conn = arc.redis.get().await.ok()?;
+    let raw: String = conn.get(format!("user:{}", user_id)).await.ok()?;
+    serde_json::from_str(&raw).ok()
+}
+
+async fn cache_user_data(arc: &Arc<Ctx>, user_id: u64, data: &DiscordUserFormat) {
+    let mut conn = match arc.redis.get().await {
+        Ok(conn) => conn,
+        Err(_) => return,
+    };
+    if let Ok(raw) = serde_json::to_string(data) {
+        let _: Result<(), _> = conn.set_ex(format!("user:{}", user_id), raw, arc.cache_ttl).await;
+    }
+}
+
+async fn get_cached_bytes(arc: &Arc<Ctx>, key: &str) -> Option<Vec<u8>> {
+    let mut conn = arc.redis.get().await.ok()?;
+    conn.get(format!("avatar:{}", key)).await.ok()
+}
+
+async fn cache_bytes(arc: &Arc<Ctx>, key: &str, data: &[u8]) {
+    let mut conn = match arc.redis.get().await {
+        Ok(conn) => conn,
+        Err(_) => return,
+    };
+    let _: Result<(), _> = conn.set_ex(format!("avatar:{}", key), data, arc.cache_ttl).await;
+}
+
+async fn get_user_data(arc: &Arc<Ctx>, user_id: u64) -> anyhow::Result<DiscordUserFormat> {
+    let mut last_retry_after = 1.0;
+    for _ in 0..MAX_RATE_LIMIT_ATTEMPTS {
+        arc.rate_limiter.wait_if_needed(USER_ENDPOINT).await;
+        let request = Request::builder()
+            .method(Method::GET)
+            .uri(format!("https://discord.com/api/v10/users/{}", user_id))
+            .header("accept", "application/json")
+            .header("authorization", format!("Bot {}", arc.token))
+            .body(Body::empty())?;
+        let mut x = arc.client.request(request).await?;
+        let headers = x.headers().clone();
+        let bucket = headers.get("x-ratelimit-bucket").and_then(|v| v.to_str().ok()).map(String::from);
+        let remaining = headers.get("x-ratelimit-remaining").and_then(|v| v.to_str().ok()).and_then(|v| v.parse::<i64>().ok());
+        let reset_after = headers.get("x-ratelimit-reset-after").and_then(|v| v.to_str().ok()).and_then(|v| v.parse::<f64>().ok());
+        arc.rate_limiter.record(USER_ENDPOINT, bucket, remaining, reset_after).await;
+
+        if x.status() == StatusCode::TOO_MANY_REQUESTS {
+            let body = hyper::body::to_bytes(x.body_mut()).await?;
+            let retry_after = headers.get("retry-after")
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.parse::<f64>().ok())
+                .or_else(|| serde_json::from_slice::<Value>(&body).ok()
+                    .and_then(|v| v.get("retry_after").and_then(|v| v.as_f64())))
+                .unwrap_or(1.0);
+            last_retry_after = retry_after;
+            let is_global = headers.contains_key("x-ratelimit-global");
+            tracing::warn!(global = is_global, retry_after, "rate limited by discord");
+            if is_global {
+                arc.rate_limiter.pause_global(Instant::now() + Duration::from_secs_f64(retry_after)).await;
+            }
+            tokio::time::sleep(Duration::from_secs_f64(retry_after)).await;
+            continue;
+        }
+
+        let body = hyper::body::to_bytes(x.body_mut()).await?;
+        let json_data = String::from_utf8(Vec::from(body))?;
+        let json: DiscordUserFormat = serde_json::from_str(&json_data)?;
+        return Ok(json);
+    }
+    Err(RateLimitExhausted { retry_after: last_retry_after.ceil() as u64 }.into())
+}
+
+const VALID_AVATAR_SIZES: [u32; 9] = [16, 32, 64, 128, 256, 512, 1024, 2048, 4096];
+
+fn query_param<'a>(query: &'a str, key: &str) -> Option<&'a str> {
+    query.split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(k, _)| *k == key)
+        .map(|(_, value)| value)
+}
+
+fn parse_size_param(query: &str) -> Option<u32> {
+    query_param(query, "size")
+        .and_then(|value| value.parse::<u32>().ok())
+        .filter(|size| VALID_AVATAR_SIZES.contains(size))
+}
+
+fn hex_decode(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len()).step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn verify_signature(key: &hmac::Key, path: &str, sig_hex: &str) -> bool {
+    match hex_decode(sig_hex) {
+        Some(sig) => hmac::verify(key, path.as_bytes(), &sig).is_ok(),
+        None => false,
+    }
+}
+
+fn get_avatar_url(json: &DiscordUserFormat, size: Option<u32>, accept_webp: bool) -> anyhow::Result<(String, &'static str)> {
+    tracing::debug!(user_id = %json.id, username = %json.username, discriminator = %json.discriminator, "resolved avatar url");
+    let (mut avatar_url, content_type) = match &json.avatar {
+        None => (default_avatar_url(&json.discriminator, json.id.parse::<u64>()?)?, "image/png"),
+        Some(avatar_hash) => {
+            let (ext, content_type) = if avatar_hash.starts_with("a_") {
+                ("gif", "image/gif")
+            } else if accept_webp {
+                ("webp", "image/webp")
+            } else {
+                ("png", "image/png")
+            };
+            (format!("https://cdn.discordapp.com/avatars/{}/{}.{}", json.id, avatar_hash, ext), content_type)
+        }
     };
-    Ok(avatar_url)
+    if let Some(size) = size {
+        avatar_url = format!("{}?size={}", avatar_url, size);
+    }
+    Ok((avatar_url, content_type))
+}
+
+
+fn capture_error(err: &anyhow::Error) {
+    sentry::integrations::anyhow::capture_anyhow(err);
 }
 
+fn capture_message(msg: &str) {
+    sentry::capture_message(msg, sentry::Level::Error);
+}
 
 fn make_err(err: u16, text: &str) -> anyhow::Result<Response<Body>> {
     return Ok(Response::builder()
@@ -61,7 +242,19 @@ fn make_err(err: u16, text: &str) -> anyhow::Result<Response<Body>> {
         .body(format!("{} {}", err, text).into())?);
 }
 
+fn make_err_retry_after(err: u16, text: &str, retry_after: u64) -> anyhow::Result<Response<Body>> {
+    return Ok(Response::builder()
+        .status(err)
+        .header("Retry-After", retry_after)
+        .body(format!("{} {}", err, text).into())?);
+}
+
 async fn resp(arc: Arc<Ctx>, req: Request<Body>) -> anyhow::Result<Response<Body>> {
+    let accept_webp = req.headers().get("accept")
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.contains("image/webp"));
+    let query = req.uri().query().unwrap_or("");
+    let size = parse_size_param(query);
     let x = req.uri().path();
     if x == "/" {
         return Ok(Response::builder()
@@ -73,19 +266,27 @@ async fn resp(arc: Arc<Ctx>, req: Request<Body>) -> anyhow::Result<Response<Body
         None => return make_err(404, "Not found"),
         Some(request) => request,
     };
+    if let Some(signing_key) = &arc.signing_key {
+        let valid = query_param(query, "sig").is_some_and(|sig| verify_signature(signing_key, x, sig));
+        if !valid {
+            return make_err(403, "Missing or invalid signature");
+        }
+    }
     if let Some(userid) = request.strip_suffix(".png") {
-        return respond_with_image(arc, userid).await;
+        return respond_with_image(arc, userid, size, accept_webp).await;
     }
     if let Some(userid) = request.strip_suffix(".json") {
-        return respond_with_json(arc, userid).await;
+        return respond_with_json(arc, userid, size, accept_webp).await;
     }
     return make_err(404, "Invalid format");
 }
 
-fn default_avatar_url(discrim: &str) -> anyhow::Result<String> {
-    let d = discrim.parse::<u16>()?;
-    let bare = d % 5;
-    Ok(format!("https://cdn.discordapp.com/embed/avatars/{}.png", bare))
+fn default_avatar_url(discrim: &str, user_id: u64) -> anyhow::Result<String> {
+    let index = match discrim.parse::<u16>() {
+        Ok(0) | Err(_) => (user_id >> 22) % 6,
+        Ok(d) => (d % 5) as u64,
+    };
+    Ok(format!("https://cdn.discordapp.com/embed/avatars/{}.png", index))
 }
 
 #[derive(Serialize, Debug)]
@@ -96,9 +297,9 @@ struct ResponseUserFormat {
     banner: Option<String>,
 }
 
-async fn respond_with_json(arc: Arc<Ctx>, userid: &str) -> anyhow::Result<Response<Body>> {
+async fn respond_with_json(arc: Arc<Ctx>, userid: &str, size: Option<u32>, accept_webp: bool) -> anyhow::Result<Response<Body>> {
     let json = unwrap_resp!(get_discord_data_for(&arc, userid).await?);
-    let avatar_url = get_avatar_url(&json)?;
+    let (avatar_url, _) = get_avatar_url(&json, size, accept_webp)?;
     let response = ResponseUserFormat {
         username: json.username,
         discriminator: json.discriminator,
@@ -116,60 +317,115 @@ async fn get_discord_data_for(arc: &Arc<Ctx>, userid: &str) -> anyhow::Result<an
         Err(_) => return make_err(404, "Not found").map(Err),
         Ok(num) => num,
     };
-    Ok(Ok(match get_user_data(&arc.client, &arc.token, num_id).await {
+    if let Some(cached) = get_cached_user_data(arc, num_id).await {
+        return Ok(Ok(cached));
+    }
+    let user_data = match get_user_data(arc, num_id).await {
         Err(e) => {
-            eprintln!("Got error from discord: {:?}", e);
-            return make_err(502, "Discord failed to respond").map(Err)
+            tracing::error!(user_id = num_id, error = ?e, "got error from discord");
+            capture_error(&e);
+            return match e.downcast_ref::<RateLimitExhausted>() {
+                Some(rl) => make_err_retry_after(503, "Rate limited by discord", rl.retry_after).map(Err),
+                None => make_err(502, "Discord failed to respond").map(Err),
+            };
         },
         Ok(user_data) => user_data,
-    }))
+    };
+    cache_user_data(arc, num_id, &user_data).await;
+    Ok(Ok(user_data))
 }
 
-async fn respond_with_image(arc: Arc<Ctx>, userid: &str) -> anyhow::Result<Response<Body>> {
+async fn respond_with_image(arc: Arc<Ctx>, userid: &str, size: Option<u32>, accept_webp: bool) -> anyhow::Result<Response<Body>> {
     let json = unwrap_resp!(get_discord_data_for(&arc, userid).await?);
-    let avatar_url = match get_avatar_url(&json) {
+    let (avatar_url, content_type) = match get_avatar_url(&json, size, accept_webp) {
         Err(_) => return make_err(502, "Discord failed to respond"),
-        Ok(avatar_url) => avatar_url,
+        Ok(result) => result,
     };
+    if let Some(cached) = get_cached_bytes(&arc, &avatar_url).await {
+        return Ok(Response::builder()
+            .status(200)
+            .header("content-type", content_type)
+            .body(cached.into())?);
+    }
     let resp = match arc.client.get(avatar_url.parse()?).await {
         Err(_) => return make_err(502, &format!("Discord failed to supply avatar for url: {}", avatar_url)),
         Ok(avatar_data) => avatar_data,
     };
+    let bytes = hyper::body::to_bytes(resp.into_body()).await?;
+    cache_bytes(&arc, &avatar_url, &bytes).await;
     Ok(Response::builder()
         .status(200)
-        .header("content-type", "image/png")
-        .body(resp.into_body())?)
+        .header("content-type", content_type)
+        .body(bytes.into())?)
 }
 
 
 struct Ctx {
     client: Client<HttpsConnector<HttpConnector>>,
     token: String,
+    redis: Pool<RedisConnectionManager>,
+    cache_ttl: u64,
+    rate_limiter: RateLimiter,
+    signing_key: Option<hmac::Key>,
 }
 
 async fn wrap_error(arc: Arc<Ctx>, req: Request<Body>) -> anyhow::Result<Response<Body>> {
-    return match resp(arc, req).await {
+    let method = req.method().clone();
+    let path = req.uri().path().to_string();
+    let user_id = path.strip_prefix("/avatar/")
+        .and_then(|rest| rest.split('.').next())
+        .unwrap_or_default()
+        .to_string();
+    let span = tracing::info_span!("request", %method, %path, %user_id, status = tracing::field::Empty, latency_ms = tracing::field::Empty);
+    let start = Instant::now();
+    let result = resp(arc, req).instrument(span.clone()).await;
+    let latency_ms = start.elapsed().as_millis() as u64;
+    span.record("latency_ms", latency_ms);
+    return match result {
         Err(e) => {
-            eprintln!("{:?}", e);
+            tracing::error!(error = ?e, "unhandled error while serving request");
+            capture_error(&e);
+            span.record("status", 500);
             Ok(Response::builder()
                 .status(500)
                 .body("500 Internal Error".into())?)
         }
-        Ok(o) => Ok(o)
+        Ok(o) => {
+            span.record("status", o.status().as_u16());
+            if o.status().is_server_error() {
+                capture_message(&format!("{} {} -> {}", method, path, o.status()));
+            }
+            Ok(o)
+        }
     };
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
+    tracing_subscriber::fmt::init();
+    let _sentry_guard = env::var("SENTRY_DSN").ok().map(|dsn| {
+        let mut options = sentry::ClientOptions::default();
+        options.release = sentry::release_name!();
+        sentry::init((dsn, options))
+    });
     let token = env::var("TOKEN")?;
     let portstr = env::var("PORT")?;
     let port = portstr.parse::<u16>()?;
-    println!("Running with token: {}", token);
+    tracing::info!(token_configured = !token.is_empty(), "starting up");
     let https = HttpsConnector::new();
     let client = Client::builder()
         .build::<_, Body>(https);
-    let arc = Arc::new(Ctx { client, token });
-    let addr = SocketAddr::from(([127, 0, 0, 1], port));
+    let redis_url = env::var("REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1/".to_string());
+    let cache_ttl = env::var("CACHE_TTL").ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(DEFAULT_CACHE_TTL);
+    let redis_manager = RedisConnectionManager::new(redis_url)?;
+    let redis = Pool::builder().build(redis_manager).await?;
+    let signing_key = env::var("SIGNING_KEY").ok()
+        .map(|key| hmac::Key::new(hmac::HMAC_SHA256, key.as_bytes()));
+    let arc = Arc::new(Ctx { client, token, redis, cache_ttl, rate_limiter: RateLimiter::default(), signing_key });
+    let host = env::var("HOST").unwrap_or_else(|_| "127.0.0.1".to_string());
+    let addr = SocketAddr::new(host.parse()?, port);
     let service = make_service_fn(|_conn| {
         let carc = Arc::clone(&arc);
         async move {
@@ -177,7 +433,43 @@ async fn main() -> anyhow::Result<()> {
         }
     });
 
-    let server = Server::bind(&addr).serve(service);
+    let shutdown_timeout = env::var("SHUTDOWN_TIMEOUT").ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT_SECS);
+    let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel();
+    let server = Server::bind(&addr).serve(service).with_graceful_shutdown(async {
+        shutdown_rx.await.ok();
+    });
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        tracing::info!("shutdown signal received, draining in-flight requests");
+        let _ = shutdown_tx.send(());
+        tokio::time::sleep(Duration::from_secs(shutdown_timeout)).await;
+        tracing::error!(shutdown_timeout, "graceful shutdown timed out, forcing exit");
+        std::process::exit(1);
+    });
+    tracing::info!(%addr, "listening");
     server.await?;
+    tracing::info!("shut down gracefully");
     Ok(())
 }
+
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c().await.expect("failed to install SIGINT handler");
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to install SIGTERM handler")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}